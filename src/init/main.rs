@@ -1,37 +1,110 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     env,
     ffi::CString,
     os::{
-        fd::{AsRawFd, RawFd},
+        fd::{AsRawFd, FromRawFd, RawFd},
         unix::net::UnixStream,
     },
     ptr,
-    sync::RwLock,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
-use init::INIT_ENV_FORMAT;
-use mio::{net::UnixListener, Events, Interest, Poll, Token};
+use init::{LISTEN_FDS_START, NOTIFY_ENV_FORMAT};
+use mio::{
+    net::{UnixDatagram, UnixListener},
+    Events, Interest, Poll, Token,
+};
+use tokio::sync::Notify;
 
 const LISTENER: Token = Token(0);
 
-// Service name -> socket
-type ServiceMap = BTreeMap<&'static str, &'static str>;
+/// How a service should be treated once it exits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Restart {
+    /// Always respawn, regardless of how the service exited.
+    Always,
+    /// Only respawn if the service exited with a non-zero status or was killed by a signal.
+    OnFailure,
+    /// Never respawn immediately; socket activation still re-arms, so a new connection can start it again.
+    Never,
+}
+
+/// Which kind of Unix domain socket a service's activation fd should be, so `socket_listener`
+/// knows whether to bind a `SOCK_STREAM` listener or a `SOCK_DGRAM` socket and pass it on the same
+/// way.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SocketKind {
+    /// Connection-oriented, accept()ed by the service itself (the current default).
+    Stream,
+    /// Connectionless, for services like logging sinks or metrics collectors that just want to
+    /// recv/send datagrams without ever accept()ing.
+    Datagram,
+}
+
+struct ServiceConfig {
+    socket: &'static str,
+    kind: SocketKind,
+    restart: Restart,
+    // Services that must report READY=1 before this one is spawned. Combines what systemd splits
+    // into `After=`/`Requires=`, since here ordering and the hard dependency are the same thing.
+    depends_on: &'static [&'static str],
+}
+
+// Service name -> config.
+type ServiceMap = BTreeMap<&'static str, ServiceConfig>;
 
 // Initialized only once. We can't register services at runtime, which we probably don't want anyway.
 static SERVICE_MAP: RwLock<ServiceMap> = RwLock::new(ServiceMap::new());
 
+// Service name -> whether it has reported READY=1 yet.
+static READY: RwLock<BTreeMap<&'static str, bool>> = RwLock::new(BTreeMap::new());
+
+// Service name -> notifier woken up whenever that service becomes ready, so dependents can await
+// readiness instead of polling for it.
+static READY_NOTIFY: RwLock<BTreeMap<&'static str, Arc<Notify>>> = RwLock::new(BTreeMap::new());
+
+// Service name -> raw fd of its listener, so a crashed service can be respawned directly instead of waiting for the
+// next incoming connection to re-trigger socket activation.
+static LISTENER_FDS: RwLock<BTreeMap<&'static str, RawFd>> = RwLock::new(BTreeMap::new());
+
+// pid -> service name, so that waitpid() in the main loop can tell which service just died.
+static PID_MAP: RwLock<BTreeMap<libc::pid_t, &'static str>> = RwLock::new(BTreeMap::new());
+
+// Services that currently have a live child process. Since a service's listener stays registered
+// with its poller for the whole process lifetime (so it can re-arm after a crash), an incoming
+// connection/datagram while the service is already running just means the running service hasn't
+// drained its socket yet, not that a new instance should be spawned for it.
+static RUNNING: RwLock<BTreeMap<&'static str, bool>> = RwLock::new(BTreeMap::new());
+
+/// How many times a service may restart within [`RESTART_WINDOW`] before we give up on it.
+const MAX_RESTARTS: usize = 5;
+/// The sliding window restarts are counted over.
+const RESTART_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct RestartState {
+    // Timestamps of restarts within the current window, oldest first.
+    restarts: VecDeque<Instant>,
+    // Set once a service exceeds MAX_RESTARTS within RESTART_WINDOW; it is never spawned again.
+    disabled: bool,
+}
+
+// Service name -> restart bookkeeping.
+static RESTART_STATE: RwLock<BTreeMap<&'static str, RestartState>> = RwLock::new(BTreeMap::new());
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
     parse();
 
     // Open all file descriptors.
-    for (service, socket) in SERVICE_MAP.read().unwrap().iter() {
+    for (service, config) in SERVICE_MAP.read().unwrap().iter() {
         // socket_listener will call service_spawner, which might eventually call spawn_service, which write locks
         // SERVICE_MAP. This is not a problem, since before dropping this read lock, no other process might do a
         // service request, which will trigger spawn_service, since no other process can't run at this point.
-        socket_listener(service, socket).await;
+        socket_listener(service, config.socket, config.kind).await;
     }
 
     log::info!("init done creating socket listeners");
@@ -45,59 +118,124 @@ async fn main() {
     // start_service("serviceB");
     // start_service("serviceC");
 
-    // Note that the information from waitpid allows us to restart services (e.g., by notifying the async task
-    // responsible for starting that service).
     loop {
-        let dead_child = unsafe { libc::waitpid(-1, ptr::null_mut(), 0) };
+        let mut status: libc::c_int = 0;
+        let dead_child = unsafe { libc::waitpid(-1, &mut status, 0) };
         if dead_child == -1 {
             continue;
         }
-        log::info!("Child {} died", dead_child);
+
+        let Some(service) = PID_MAP.write().unwrap().remove(&dead_child) else {
+            continue;
+        };
+        log::info!("Child {} ({}) died", dead_child, service);
+
+        handle_exit(service, status).await;
     }
 }
 
-/// In practice this would parse some init.rc or some config files to retrieve the sockets of services using socket
-/// activation.
+/// In practice this would parse some init.rc or some config files to retrieve the sockets, restart policies and
+/// dependency ordering of services using socket activation.
 fn parse() {
     let mut service_map = SERVICE_MAP.write().unwrap();
-    service_map.insert("serviceA", "service_a_socket");
-    service_map.insert("serviceB", "service_b_socket");
-    service_map.insert("serviceC", "service_c_socket");
+    service_map.insert(
+        "serviceA",
+        ServiceConfig {
+            socket: "service_a_socket",
+            kind: SocketKind::Stream,
+            restart: Restart::Always,
+            depends_on: &["serviceB"],
+        },
+    );
+    service_map.insert(
+        "serviceB",
+        ServiceConfig {
+            socket: "service_b_socket",
+            kind: SocketKind::Stream,
+            restart: Restart::OnFailure,
+            depends_on: &["serviceC"],
+        },
+    );
+    service_map.insert(
+        "serviceC",
+        ServiceConfig {
+            socket: "service_c_socket",
+            kind: SocketKind::Stream,
+            restart: Restart::Never,
+            depends_on: &[],
+        },
+    );
+    service_map.insert(
+        "serviceD",
+        ServiceConfig {
+            socket: "service_d_socket",
+            kind: SocketKind::Datagram,
+            restart: Restart::Always,
+            depends_on: &[],
+        },
+    );
 }
 
 /// Spawns a service by connecting to its socket.
 fn start_service(service: &'static str) {
     let service_map = SERVICE_MAP.read().unwrap();
-    let socket = service_map.get(service).unwrap();
+    let socket = service_map.get(service).unwrap().socket;
     let _stream = UnixStream::connect(socket).unwrap();
 }
 
-/// Creates and listens to a Unix socket.
-///
-/// Once a connections comes in, a service is started that will handle the connection.
-/// The created Listener is moved into the async task and will be dropped, once that task returns. This will be after
-/// forking the service, so dropping is ok.
-///
-/// Note that this assumes services never die. Once we spawned the service we just return.
-/// If we want dynamic restarting this listener needs to continously listen to the socket in order to be able to
-/// restart services.
-async fn socket_listener(service: &'static str, socket: &'static str) {
-    let mut listener = UnixListener::bind(socket).unwrap();
-    log::info!("Listening to {socket} (service: {service})");
+/// Creates and listens to a Unix socket of the given `kind`, and records its fd so the service
+/// behind it can be respawned later.
+async fn socket_listener(service: &'static str, socket: &'static str, kind: SocketKind) {
+    match kind {
+        SocketKind::Stream => {
+            let mut listener = UnixListener::bind(socket).unwrap();
+            log::info!("Listening to {socket} (service: {service})");
+
+            LISTENER_FDS
+                .write()
+                .unwrap()
+                .insert(service, listener.as_raw_fd());
 
-    tokio::spawn(async move {
-        service_spawner(service, &mut listener).await;
-    });
+            tokio::spawn(async move {
+                stream_spawner(service, &mut listener).await;
+            });
+        }
+        SocketKind::Datagram => {
+            let mut datagram = UnixDatagram::bind(socket).unwrap();
+            log::info!("Listening to {socket} (service: {service}, datagram)");
+
+            LISTENER_FDS
+                .write()
+                .unwrap()
+                .insert(service, datagram.as_raw_fd());
+
+            tokio::spawn(async move {
+                datagram_spawner(service, &mut datagram).await;
+            });
+        }
+    }
 }
 
-/// Spawns a service when there is some incoming connection to the Listener.
-///
-/// Polls the Listener file descriptor, checking for possible events. If there is such event, a service is spawned that
-/// can then accept the incoming connection.
+/// Spawns `service` for an incoming connection or datagram, unless it's already running (its
+/// listener stays registered for the whole process lifetime so it can re-arm after a crash, but
+/// while the service is alive it's the one responsible for draining its own socket) or has been
+/// given up on after crashing too many times.
+async fn maybe_spawn(service: &'static str, fd: RawFd) {
+    if is_running(service) || is_disabled(service) {
+        return;
+    }
+
+    log::info!("Incoming activity for {}", service);
+    wait_for_dependencies(service).await;
+    spawn_service(service, &[fd]);
+}
+
+/// Hands every incoming connection on the Listener to [`maybe_spawn`], which spawns the service unless it's
+/// already running or disabled.
 ///
-/// After the service is spawned we return. If we implement automatic restarting of services, this should not return,
-/// since returning also drops the Listener.
-async fn service_spawner(service: &'static str, listener: &mut UnixListener) {
+/// Polls the Listener file descriptor for the lifetime of the process, so the same socket keeps spawning (or
+/// re-arming) the service after it exits, rather than being dropped after the first connection.
+async fn stream_spawner(service: &'static str, listener: &mut UnixListener) -> ! {
     let mut poll = Poll::new().unwrap();
     poll.registry()
         .register(listener, LISTENER, Interest::READABLE)
@@ -105,41 +243,235 @@ async fn service_spawner(service: &'static str, listener: &mut UnixListener) {
 
     let mut events = Events::with_capacity(128);
 
-    poll.poll(&mut events, None).unwrap();
+    loop {
+        poll.poll(&mut events, None).unwrap();
 
-    for event in events.iter() {
-        match event.token() {
-            LISTENER => {
-                log::info!("Incoming connection for {}", service);
-                spawn_service(service, listener.as_raw_fd());
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => maybe_spawn(service, listener.as_raw_fd()).await,
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }
 }
 
+/// The datagram counterpart to [`stream_spawner`]: there's no `accept`, so readability just means
+/// there's at least one datagram waiting, which is spawned for the same way.
+async fn datagram_spawner(service: &'static str, socket: &mut UnixDatagram) -> ! {
+    let mut poll = Poll::new().unwrap();
+    poll.registry()
+        .register(socket, LISTENER, Interest::READABLE)
+        .unwrap();
+
+    let mut events = Events::with_capacity(128);
+
+    loop {
+        poll.poll(&mut events, None).unwrap();
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => maybe_spawn(service, socket.as_raw_fd()).await,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Re-arms socket activation for `service` and, depending on its restart policy and recent restart history,
+/// immediately respawns it.
+///
+/// Re-arming itself needs nothing further here: the service's spawner task keeps its listener registered with its
+/// poller, so the next incoming connection or datagram spawns the service again, unless `record_restart` has
+/// disabled it by then, in which case `maybe_spawn` will also refuse to.
+async fn handle_exit(service: &'static str, status: libc::c_int) {
+    READY.write().unwrap().remove(service);
+    RUNNING.write().unwrap().remove(service);
+
+    let succeeded = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+
+    let Some(restart) = SERVICE_MAP.read().unwrap().get(service).map(|c| c.restart) else {
+        return;
+    };
+
+    let should_restart = match restart {
+        Restart::Always => true,
+        Restart::OnFailure => !succeeded,
+        Restart::Never => false,
+    };
+    if !should_restart {
+        return;
+    }
+
+    if !record_restart(service) {
+        log::info!("{} restarted too often, giving up on it", service);
+        return;
+    }
+
+    let Some(&fd) = LISTENER_FDS.read().unwrap().get(service) else {
+        return;
+    };
+    wait_for_dependencies(service).await;
+    spawn_service(service, &[fd]);
+}
+
+/// Waits until every service `service` depends on (its `After=`/`Requires=` ordering) has reported readiness,
+/// starting each one if it hasn't been asked to start yet.
+async fn wait_for_dependencies(service: &'static str) {
+    let deps = SERVICE_MAP
+        .read()
+        .unwrap()
+        .get(service)
+        .map(|config| config.depends_on)
+        .unwrap_or(&[]);
+
+    for &dep in deps {
+        wait_ready(dep).await;
+    }
+}
+
+/// Waits until `service` has reported `READY=1`, starting it first if it hasn't been asked to start yet.
+async fn wait_ready(service: &'static str) {
+    if is_ready(service) {
+        return;
+    }
+
+    log::info!("waiting for {} to become ready", service);
+    start_service(service);
+
+    loop {
+        let notify = ready_notify(service);
+        let notified = notify.notified();
+        if is_ready(service) {
+            return;
+        }
+        notified.await;
+        if is_ready(service) {
+            return;
+        }
+    }
+}
+
+/// Whether `service` currently has a live child process.
+fn is_running(service: &'static str) -> bool {
+    *RUNNING.read().unwrap().get(service).unwrap_or(&false)
+}
+
+fn is_ready(service: &'static str) -> bool {
+    *READY.read().unwrap().get(service).unwrap_or(&false)
+}
+
+fn mark_ready(service: &'static str) {
+    log::info!("{} is ready", service);
+    READY.write().unwrap().insert(service, true);
+    ready_notify(service).notify_waiters();
+}
+
+fn ready_notify(service: &'static str) -> Arc<Notify> {
+    READY_NOTIFY
+        .write()
+        .unwrap()
+        .entry(service)
+        .or_insert_with(|| Arc::new(Notify::new()))
+        .clone()
+}
+
+/// Whether `service` has been given up on after exceeding `MAX_RESTARTS` within `RESTART_WINDOW`.
+fn is_disabled(service: &'static str) -> bool {
+    RESTART_STATE
+        .read()
+        .unwrap()
+        .get(service)
+        .is_some_and(|state| state.disabled)
+}
+
+/// Records a restart attempt for `service` and returns whether it's still allowed to run, i.e. whether it hasn't
+/// exceeded `MAX_RESTARTS` within `RESTART_WINDOW`.
+fn record_restart(service: &'static str) -> bool {
+    let mut state = RESTART_STATE.write().unwrap();
+    let entry = state.entry(service).or_default();
+
+    if entry.disabled {
+        return false;
+    }
+
+    let now = Instant::now();
+    entry.restarts.push_back(now);
+    while let Some(&oldest) = entry.restarts.front() {
+        if now.duration_since(oldest) > RESTART_WINDOW {
+            entry.restarts.pop_front();
+        } else {
+            break;
+        }
+    }
+
+    if entry.restarts.len() > MAX_RESTARTS {
+        entry.disabled = true;
+        return false;
+    }
+
+    true
+}
+
 /// Spawns a service.
 ///
 /// This is done with a combination of fork and exec. Should probably be done with posix_spawn.
 ///
-/// Unsets FD_CLOEXEC for the file descriptor we want to pass and makes it blocking (so for the service it looks like
-/// a normal blocking UnixListener).
-fn spawn_service(service: &'static str, socket: RawFd) {
+/// Also sets up a notification socket pair so the service can report readiness back to us: the child keeps its end
+/// alive across exec and reads its fd number from an environment variable, while we keep polling our end for
+/// `READY=1`/`STATUS=...` messages for as long as the service runs.
+fn spawn_service(service: &'static str, sockets: &[RawFd]) {
+    let (parent_notify, child_notify) = notify_socketpair();
+
     match unsafe { libc::fork() } {
         -1 => panic!("fork failed"),
         0 => {
-            // File descriptors in Rust are per default FD_CLOEXEC. Lets remove that flag for our socket, so it
-            // survives exec.
-            unset_cloexec(socket);
-            // Set file descriptor to blocking, so it appears like a UnixListener from std for services.
-            unset_nonblocking(socket);
-            exec(service, socket)
+            unsafe { libc::close(parent_notify) };
+            exec(service, sockets, child_notify)
+        }
+        pid => {
+            unsafe { libc::close(child_notify) };
+            PID_MAP.write().unwrap().insert(pid, service);
+            RUNNING.write().unwrap().insert(service, true);
+            tokio::spawn(notify_listener(service, parent_notify));
         }
-        _pid => {
-            // The parent is done here.
+    }
+}
 
-            // Note: we could keep a map of pid -> service, so we can automatically restart exited services.
-            return;
+/// Creates a connected pair of `AF_UNIX` datagram sockets used by a service to report readiness to us.
+fn notify_socketpair() -> (RawFd, RawFd) {
+    let mut fds = [0 as RawFd; 2];
+    assert_ne!(
+        unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) },
+        -1
+    );
+    (fds[0], fds[1])
+}
+
+/// Reads sd_notify-style messages sent by `service` over `fd` for as long as it keeps sending them.
+async fn notify_listener(service: &'static str, fd: RawFd) {
+    let socket = unsafe { std::os::unix::net::UnixDatagram::from_raw_fd(fd) };
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+    let Ok(socket) = tokio::net::UnixDatagram::from_std(socket) else {
+        return;
+    };
+
+    let mut buf = vec![0; 256];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(n) => handle_notify(service, &buf[..n]),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Interprets a single sd_notify-style datagram from `service`.
+fn handle_notify(service: &'static str, message: &[u8]) {
+    for line in String::from_utf8_lossy(message).lines() {
+        match line {
+            "READY=1" => mark_ready(service),
+            other => log::trace!("{} notified: {}", service, other),
         }
     }
 }
@@ -155,30 +487,64 @@ fn unset_cloexec(fd: RawFd) {
     assert_ne!(unsafe { libc::fcntl(fd, libc::F_SETFD, new_flags) }, -1);
 }
 
-/// Set the file descriptor to blocking.
-fn unset_nonblocking(fd: RawFd) {
-    let mut nonblocking = false as libc::c_int;
-    unsafe { libc::ioctl(fd, libc::FIONBIO, &mut nonblocking) };
-}
-
-/// Executes a service.
+/// Executes a service, handing it `sockets` via the systemd-style socket-activation protocol and `notify` as its
+/// readiness-notification socket.
 ///
-/// Sets up the correct service path and arguments for the service. The file descriptor to be passed will be set as
-/// environment variable.
+/// Sets up the correct service path and arguments for the service. `sockets` is rearranged into
+/// the contiguous block of fds starting at [`LISTEN_FDS_START`] that the protocol expects, with
+/// FD_CLOEXEC cleared so each survives the exec below, then `LISTEN_FDS`, `LISTEN_PID` and
+/// `LISTEN_FDNAMES` are set to describe that block to the child. `notify` keeps its fd number
+/// unless that number falls inside the block, in which case it's first moved above it; either
+/// way its FD_CLOEXEC flag is cleared.
 ///
 /// Does an execve system call.
 ///
 /// Panics if exec fails, since how would we even recover from that?
-fn exec(service: &'static str, socket: RawFd) -> ! {
+fn exec(service: &'static str, sockets: &[RawFd], notify: RawFd) -> ! {
+    let target_start = LISTEN_FDS_START;
+    let target_end = target_start + sockets.len() as RawFd;
+
+    // Moving sockets[i] into slot target_start + i one at a time can clobber a not-yet-moved
+    // source fd whose own number happens to fall inside that target range (e.g. sockets = [4, 3]
+    // with target_start = 3: dup2(4, 3) overwrites fd 3 before it's read at i = 1). Dodge this by
+    // first relocating every source fd (listeners and the notify socket alike) that sits inside
+    // the target range to a scratch fd above it, so the in-place loop below never dup2s into a
+    // slot one of its own remaining sources still lives in.
+    let mut sockets = sockets.to_vec();
+    let mut notify = notify;
+    for fd in sockets.iter_mut().chain(std::iter::once(&mut notify)) {
+        if (target_start..target_end).contains(fd) {
+            let scratch = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, target_end) };
+            assert_ne!(scratch, -1);
+            unsafe { libc::close(*fd) };
+            *fd = scratch;
+        }
+    }
+
+    for (i, &fd) in sockets.iter().enumerate() {
+        let target = target_start + i as RawFd;
+        if fd != target {
+            assert_ne!(unsafe { libc::dup2(fd, target) }, -1);
+        }
+        unset_cloexec(target);
+    }
+    unset_cloexec(notify);
+
     let program_path = "target/debug/".to_string() + service;
     let program = CString::new(program_path).unwrap();
 
     // We start without any arguments, so we just use the program name as first argument.
     let argv = vec![program.as_ptr(), ptr::null()];
 
-    // Set an environment variable for the passed file descriptor.
+    // Every fd currently belongs to this one service, so the same logical name is repeated once
+    // per fd.
+    let names = vec![service; sockets.len()].join(":");
+
     unsafe {
-        env::set_var(INIT_ENV_FORMAT, format!("{}", socket));
+        env::set_var("LISTEN_FDS", sockets.len().to_string());
+        env::set_var("LISTEN_PID", libc::getpid().to_string());
+        env::set_var("LISTEN_FDNAMES", names);
+        env::set_var(NOTIFY_ENV_FORMAT, notify.to_string());
     }
 
     unsafe { libc::execvp(program.as_ptr(), argv.as_ptr()) };
@@ -1,12 +1,32 @@
 use std::{
     env,
     io::{Read, Write},
-    os::unix::net::UnixStream,
+    mem,
+    os::unix::{
+        io::RawFd,
+        net::{UnixDatagram, UnixStream},
+    },
+    ptr,
 };
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
 // Do we really want to expose this into a library used by the user?
 pub const INIT_ENV_FORMAT: &'static str = "INIT_FD";
 
+// Env var carrying the fd of the `AF_UNIX` control socket used to pass listener file
+// descriptors via `SCM_RIGHTS`. Consumed (and unset) by `init_get_fds`. The init binary does
+// not set it: it hands listeners over with `LISTEN_FDS`/`LISTEN_PID`/`LISTEN_FDNAMES` instead.
+pub const INIT_CONTROL_ENV_FORMAT: &'static str = "INIT_CONTROL_FD";
+
+/// The first fd of the contiguous block that socket-activated listeners are passed in under the
+/// `LISTEN_FDS`/`LISTEN_PID`/`LISTEN_FDNAMES` protocol, matching the systemd convention.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// Env var carrying the fd of the notification socket used to report readiness back to init,
+// analogous to systemd's `NOTIFY_SOCKET`. Set by init right before it execs the child.
+pub const NOTIFY_ENV_FORMAT: &'static str = "NOTIFY_FD";
+
 pub struct Error();
 
 impl std::fmt::Debug for Error {
@@ -40,23 +60,224 @@ pub fn init_get_fd() -> Result<i32, Error> {
     }
 }
 
+/// Returns the set of raw file descriptors passed to this process with [`send_fds`] over the
+/// control socket named by `INIT_CONTROL_FD`.
+///
+/// Expects that an environment variable is set that indicates the control socket's file
+/// descriptor number. Unsets the environment variable after reading it.
+///
+/// This will fail if the service was not spawned by init, or if receiving the descriptors over
+/// the control socket fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use init::init_get_fds;
+///
+/// let Ok(socket_fds) = init_get_fds() else {
+///     // handle error
+/// };
+/// ```
+pub fn init_get_fds() -> Result<Vec<RawFd>, Error> {
+    let control = match env::var(INIT_CONTROL_ENV_FORMAT) {
+        Ok(value) => value.parse::<RawFd>().or(Err(Error()))?,
+        Err(_err) => return Err(Error()),
+    };
+    env::remove_var(INIT_CONTROL_ENV_FORMAT);
+
+    recv_fds(control)
+}
+
+/// Returns the file descriptors passed to this process under the systemd-style socket-activation
+/// protocol (`LISTEN_FDS`/`LISTEN_PID`/`LISTEN_FDNAMES`).
+///
+/// Validates `LISTEN_PID` against our own pid before trusting `LISTEN_FDS`, so descriptors meant
+/// for some other process further down an exec chain are never picked up. Unsets all three
+/// environment variables after reading them, whether or not they validated.
+///
+/// Returns an empty `Vec` if this process was not socket-activated.
+///
+/// # Examples
+///
+/// ```rust
+/// use init::listen_fds;
+///
+/// for fd in listen_fds() {
+///     // use fd
+/// }
+/// ```
+pub fn listen_fds() -> Vec<RawFd> {
+    read_listen_fds().0
+}
+
+/// Like [`listen_fds`], but pairs each descriptor with its logical name taken from
+/// `LISTEN_FDNAMES`.
+pub fn listen_fds_with_names() -> Vec<(RawFd, String)> {
+    let (fds, names) = read_listen_fds();
+    fds.into_iter().zip(names).collect()
+}
+
+/// Reads, validates and unsets the `LISTEN_FDS`/`LISTEN_PID`/`LISTEN_FDNAMES` triple.
+fn read_listen_fds() -> (Vec<RawFd>, Vec<String>) {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let pid_matches = pid
+        .and_then(|value| value.parse::<libc::pid_t>().ok())
+        .is_some_and(|pid| pid == unsafe { libc::getpid() });
+    if !pid_matches {
+        return (Vec::new(), Vec::new());
+    }
+
+    let count = count.and_then(|value| value.parse::<RawFd>().ok()).unwrap_or(0);
+    let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+
+    let names = names
+        .map(|value| value.split(':').map(str::to_string).collect())
+        .unwrap_or_else(|| vec![String::new(); fds.len()]);
+
+    (fds, names)
+}
+
+/// Sends `fds` to the other end of `control` as an `SCM_RIGHTS` ancillary message.
+///
+/// `control` must be a connected `AF_UNIX` socket. The kernel drops ancillary data that isn't
+/// attached to at least one byte of regular data, so this always carries a one-byte payload
+/// alongside the descriptors.
+///
+/// The receiving end is [`init_get_fds`]. The init binary does not call this; it passes
+/// listeners with the `LISTEN_FDS` protocol (see [`listen_fds`]).
+pub fn send_fds(control: RawFd, fds: &[RawFd]) -> Result<(), Error> {
+    if fds.is_empty() {
+        // Ancillary data without an accompanying byte of real data is dropped by the kernel, so
+        // an empty set of fds could never be received on the other end anyway.
+        return Err(Error());
+    }
+
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let cmsg_space = unsafe { libc::CMSG_SPACE((fds.len() * mem::size_of::<RawFd>()) as u32) };
+    let mut cmsg_buf = vec![0u8; cmsg_space as usize];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN((fds.len() * mem::size_of::<RawFd>()) as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+
+    if unsafe { libc::sendmsg(control, &msg, 0) } == -1 {
+        return Err(Error());
+    }
+
+    Ok(())
+}
+
+/// Receives a set of file descriptors sent with [`send_fds`] from `control`.
+///
+/// The control buffer is sized for up to `MAX_FDS` (32) descriptors; the number actually
+/// received is taken from the kernel-filled `msg_controllen`/`cmsg_len`, never assumed, since a
+/// short or malformed control message must not be read as if it contained more descriptors than
+/// it does.
+fn recv_fds(control: RawFd) -> Result<Vec<RawFd>, Error> {
+    // Large enough for any reasonable number of listeners handed to a single service.
+    const MAX_FDS: usize = 32;
+
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let cmsg_space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) };
+    let mut cmsg_buf = vec![0u8; cmsg_space as usize];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    if unsafe { libc::recvmsg(control, &mut msg, 0) } == -1 {
+        return Err(Error());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let payload_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let count = payload_len / mem::size_of::<RawFd>();
+                let data_ptr = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    fds.push(*data_ptr.add(i));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if fds.is_empty() {
+        return Err(Error());
+    }
+
+    // Descriptors received over SCM_RIGHTS come in marked FD_CLOEXEC; clear that the same way we
+    // would for a directly inherited fd, so services can use them across their own execs too.
+    for &fd in &fds {
+        unset_cloexec(fd);
+    }
+
+    Ok(fds)
+}
+
+/// Unsets FD_CLOEXEC from a given raw file descriptor.
+fn unset_cloexec(fd: RawFd) {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    assert_ne!(flags, -1);
+
+    let new_flags = flags & !libc::FD_CLOEXEC;
+    assert_ne!(unsafe { libc::fcntl(fd, libc::F_SETFD, new_flags) }, -1);
+}
+
 pub struct Worker {
     service: &'static str,
-    stream: UnixStream,
+    stream: tokio::net::UnixStream,
 }
 
 impl Worker {
+    /// Builds a `Worker` around a std `UnixStream` (as returned by `UnixListener::accept`),
+    /// handing it over to tokio after putting it in non-blocking mode.
     pub fn new(service: &'static str, stream: UnixStream) -> Worker {
+        stream.set_nonblocking(true).unwrap();
+        let stream = tokio::net::UnixStream::from_std(stream).unwrap();
         Worker { service, stream }
     }
 
-    pub fn run<F>(&mut self, f: F)
+    pub async fn run<F, Fut>(&mut self, f: F)
     where
-        F: Fn() -> (),
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = ()>,
     {
         let mut buf = vec![0; 1024];
         loop {
-            match self.stream.read(&mut buf) {
+            match self.stream.read(&mut buf).await {
                 Ok(n) => {
                     if n == 0 {
                         return;
@@ -68,11 +289,12 @@ impl Worker {
                         String::from_utf8_lossy(&buf[..n])
                     );
 
-                    f();
+                    f().await;
 
                     if self
                         .stream
-                        .write(format!("Answer from {}", self.service).as_bytes())
+                        .write_all(format!("Answer from {}", self.service).as_bytes())
+                        .await
                         .is_err()
                     {
                         log::info!("Couldn't write to stream. Exiting worker");
@@ -88,6 +310,66 @@ impl Worker {
     }
 }
 
+/// A `DatagramWorker` is the `SOCK_DGRAM` counterpart to [`Worker`], for activated sockets that
+/// are logging sinks, metrics collectors or similar: there's no `accept`, every datagram is its
+/// own message, and a reply (if any) goes back to whichever address sent it.
+pub struct DatagramWorker {
+    service: &'static str,
+    socket: tokio::net::UnixDatagram,
+}
+
+impl DatagramWorker {
+    /// Builds a `DatagramWorker` around a std `UnixDatagram` (as returned by reconstructing an
+    /// activated fd with `UnixDatagram::from_raw_fd`), handing it over to tokio after putting it
+    /// in non-blocking mode.
+    pub fn new(service: &'static str, socket: UnixDatagram) -> DatagramWorker {
+        socket.set_nonblocking(true).unwrap();
+        let socket = tokio::net::UnixDatagram::from_std(socket).unwrap();
+        DatagramWorker { service, socket }
+    }
+
+    pub async fn run<F, Fut>(&mut self, f: F)
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let mut buf = vec![0; 1024];
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((n, from)) => {
+                    log::trace!(
+                        "{} got message {}",
+                        self.service,
+                        String::from_utf8_lossy(&buf[..n])
+                    );
+
+                    f().await;
+
+                    // An unnamed sender (the common case for a connectionless client that just
+                    // fires a datagram and moves on) has no address to reply to.
+                    let Some(path) = from.as_pathname() else {
+                        continue;
+                    };
+
+                    if self
+                        .socket
+                        .send_to(format!("Answer from {}", self.service).as_bytes(), path)
+                        .await
+                        .is_err()
+                    {
+                        log::info!("Couldn't write to socket. Exiting worker");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::info!("Error while reading from socket: {}. Exiting worker", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
 pub fn request(socket: &'static str) {
     let mut stream = UnixStream::connect(socket).unwrap();
     stream.write_all(b"Asking for data").unwrap();
@@ -102,3 +384,65 @@ pub fn request(socket: &'static str) {
         }
     }
 }
+
+/// The async counterpart to [`request`], for callers running on a tokio runtime (e.g. a
+/// [`Worker`] callback) that must not block their worker thread for the round trip.
+pub async fn request_async(socket: &'static str) {
+    let mut stream = match tokio::net::UnixStream::connect(socket).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::info!("Error while connecting to socket: {}", e);
+            return;
+        }
+    };
+
+    if stream.write_all(b"Asking for data").await.is_err() {
+        log::info!("Error while writing to socket");
+        return;
+    }
+
+    let mut buf = vec![0; 1024];
+    match stream.read(&mut buf).await {
+        Ok(n) => {
+            log::trace!("Got message: {}", String::from_utf8_lossy(&buf[..n]))
+        }
+        Err(e) => {
+            log::info!("Error while reading from socket: {}", e);
+        }
+    }
+}
+
+/// Tells init that this service is ready, the sd_notify equivalent of `READY=1`.
+///
+/// This will fail if the service was not spawned by init, or if the notification couldn't be
+/// sent.
+pub fn notify_ready() -> Result<(), Error> {
+    notify("READY=1")
+}
+
+/// Tells init about a free-form status change, the sd_notify equivalent of `STATUS=...`.
+///
+/// This will fail if the service was not spawned by init, or if the notification couldn't be
+/// sent.
+pub fn notify_status(status: &str) -> Result<(), Error> {
+    notify(&format!("STATUS={}", status))
+}
+
+/// Sends a single sd_notify-style message to init over the notification socket.
+///
+/// Reads the notification socket's fd from an environment variable every time, rather than
+/// unsetting it after the first use, since a service is expected to call this repeatedly over
+/// its lifetime.
+fn notify(message: &str) -> Result<(), Error> {
+    let fd = env::var(NOTIFY_ENV_FORMAT)
+        .or(Err(Error()))?
+        .parse::<RawFd>()
+        .or(Err(Error()))?;
+
+    let n = unsafe { libc::write(fd, message.as_ptr() as *const libc::c_void, message.len()) };
+    if n < 0 {
+        return Err(Error());
+    }
+
+    Ok(())
+}
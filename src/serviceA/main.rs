@@ -14,25 +14,28 @@ async fn main() {
     setup().await;
 
     // Works with and without socket activation.
-    let listener = match init::init_get_fd() {
-        Ok(raw_fd) => unsafe { UnixListener::from_raw_fd(raw_fd) },
-        Err(_) => UnixListener::bind(SOCKET).unwrap(),
+    let listener = match init::listen_fds().first() {
+        Some(&raw_fd) => unsafe { UnixListener::from_raw_fd(raw_fd) },
+        None => UnixListener::bind(SOCKET).unwrap(),
     };
 
+    // Tell init we're up, so it can start services that depend on us instead of guessing from a timer.
+    init::notify_ready().ok();
+
     loop {
         let (stream, _address) = listener.accept().unwrap();
         let mut worker = Worker::new("A", stream);
         // Spawn a worker that receives requests, calls a function and then sends an answer
         tokio::spawn(async move {
-            worker.run(request_b);
+            worker.run(request_b).await;
         });
     }
 }
 
 /// Requests an answer from service B.
-fn request_b() {
+async fn request_b() {
     log::trace!("A needs data from B");
-    init::request("service_b_socket");
+    init::request_async("service_b_socket").await;
 }
 
 async fn setup() {
@@ -15,13 +15,16 @@ async fn main() {
 
     // We use socket activation, so lets receive the fd from init!
     // let listener = UnixListener::bind(_SOCKET).unwrap();
-    let listener = unsafe { UnixListener::from_raw_fd(init::init_get_fd().unwrap()) };
+    let listener = unsafe { UnixListener::from_raw_fd(init::listen_fds()[0]) };
+
+    // Tell init we're up, so it can start services that depend on us instead of guessing from a timer.
+    init::notify_ready().ok();
 
     loop {
         let (stream, _) = listener.accept().unwrap();
         let mut worker = Worker::new("B", stream);
         tokio::spawn(async move {
-            worker.run(|| {});
+            worker.run(|| async {}).await;
         });
     }
 }
@@ -30,9 +33,7 @@ async fn setup() {
     env_logger::init();
     log::info!("Starting");
 
+    // init won't spawn us until serviceC has reported readiness, so there's no need to check for it ourselves here
+    // like we used to.
     sleep(Duration::from_secs(2)).await;
-
-    // This service needs data from serviceA for its setup. Will fail if serviceA is not up.
-    log::trace!("B needs data from C for setup");
-    init::request("service_c_socket");
 }
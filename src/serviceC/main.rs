@@ -15,13 +15,16 @@ async fn main() {
 
     // We use socket activation, so lets receive the fd from init!
     // let listener = UnixListener::bind(_SOCKET).unwrap();
-    let listener = unsafe { UnixListener::from_raw_fd(init::init_get_fd().unwrap()) };
+    let listener = unsafe { UnixListener::from_raw_fd(init::listen_fds()[0]) };
+
+    // Tell init we're up, so it can start services that depend on us instead of guessing from a timer.
+    init::notify_ready().ok();
 
     loop {
         let (stream, _address) = listener.accept().unwrap();
         let mut worker = Worker::new("C", stream);
         tokio::spawn(async move {
-            worker.run(|| {});
+            worker.run(|| async {}).await;
         });
     }
 }
@@ -0,0 +1,32 @@
+use std::{
+    os::{fd::FromRawFd, unix::net::UnixDatagram},
+    time::Duration,
+};
+
+use init::DatagramWorker;
+use tokio::time::sleep;
+
+// We don't manually bind, instead we just receive the fd from init.
+const _SOCKET: &'static str = "service_d_socket";
+
+#[tokio::main]
+async fn main() {
+    setup().await;
+
+    // We use socket activation, so lets receive the fd from init! Unlike the other services, our
+    // socket is a datagram one: we're a logging sink, there's no connection to accept().
+    let socket = unsafe { UnixDatagram::from_raw_fd(init::listen_fds()[0]) };
+
+    // Tell init we're up, so it can start services that depend on us instead of guessing from a timer.
+    init::notify_ready().ok();
+
+    let mut worker = DatagramWorker::new("D", socket);
+    worker.run(|| async {}).await;
+}
+
+async fn setup() {
+    env_logger::init();
+    log::info!("Starting");
+
+    sleep(Duration::from_secs(1)).await;
+}